@@ -0,0 +1,262 @@
+//
+// Copyright © 2023 Arm Limited. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A self-contained HTML timeline report, used as an alternative to Chrome's trace viewer for
+//! headless/CI machines or quickly sharing a capture without asking the recipient to load it
+//! into `chrome://tracing`. The processed event list is embedded verbatim as JSON inside a
+//! `<script>` tag; a small bundled renderer then lays out one horizontal lane per process/thread
+//! (using the same `process_name`/`thread_name` metadata events `process_finalize` already
+//! writes) and draws "B"/"E" pairs as bars and "I"/"C" events as markers, with mouse-wheel zoom
+//! and drag-to-pan. Agent lifetimes and (once implemented) the `--add-timeline-bars` rows show up
+//! automatically, since they are just more processes/threads to this renderer.
+
+use serde_json::Value;
+use std::io::{self, Write};
+
+const HTML_PREFIX: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>NPU profiling timeline</title>
+<style>
+  html, body { margin: 0; height: 100%; font-family: sans-serif; font-size: 12px; background: #fff; }
+  #lanes { position: absolute; left: 0; top: 0; width: 200px; bottom: 0; overflow: hidden; border-right: 1px solid #ccc; background: #f7f7f7; }
+  #lanes div { position: absolute; left: 4px; right: 4px; overflow: hidden; white-space: nowrap; text-overflow: ellipsis; }
+  #chart { position: absolute; left: 200px; top: 0; right: 0; bottom: 0; overflow: hidden; cursor: grab; }
+  #tooltip { position: absolute; display: none; background: #333; color: #fff; padding: 4px 6px; border-radius: 3px; pointer-events: none; max-width: 480px; white-space: pre-wrap; z-index: 10; }
+  #hint { position: absolute; right: 8px; top: 4px; color: #888; }
+</style>
+</head>
+<body>
+<div id="lanes"></div>
+<svg id="chart" xmlns="http://www.w3.org/2000/svg"></svg>
+<div id="tooltip"></div>
+<div id="hint">Scroll to zoom, drag to pan</div>
+<script>
+const EVENTS = "#;
+
+const HTML_SUFFIX: &str = r##";
+(function() {
+  "use strict";
+
+  // Group raw Chrome-trace-style events into one row per (pid, tid), each holding the "B"/"E"
+  // duration pairs (stack-matched, to tolerate nesting) and "I"/"C" instant samples on that row,
+  // plus a display name taken from the "process_name"/"thread_name" metadata events.
+  const processNames = new Map();
+  const threadNames = new Map();
+  const rowsByKey = new Map();
+  let minTs = Infinity;
+  let maxTs = -Infinity;
+
+  function rowFor(pid, tid) {
+    const key = pid + ":" + tid;
+    let row = rowsByKey.get(key);
+    if (!row) {
+      row = { pid: pid, tid: tid, spans: [], markers: [], openStack: [] };
+      rowsByKey.set(key, row);
+    }
+    return row;
+  }
+
+  for (const e of EVENTS) {
+    if (e.ph === "M") {
+      if (e.name === "process_name") processNames.set(e.pid, e.args.name);
+      if (e.name === "thread_name") threadNames.set(e.pid + ":" + e.tid, e.args.name);
+      continue;
+    }
+    if (typeof e.ts !== "number") continue;
+    minTs = Math.min(minTs, e.ts);
+    maxTs = Math.max(maxTs, e.ts);
+
+    const row = rowFor(e.pid, e.tid);
+    if (e.ph === "B") {
+      row.openStack.push(e);
+    } else if (e.ph === "E") {
+      const begin = row.openStack.pop();
+      if (begin) {
+        row.spans.push({ name: begin.name, args: begin.args, cname: begin.cname, start: begin.ts, end: e.ts, depth: row.openStack.length });
+      }
+    } else if (e.ph === "I" || e.ph === "C") {
+      row.markers.push({ name: e.name, args: e.args, cname: e.cname, ts: e.ts, ph: e.ph });
+    }
+  }
+  if (!isFinite(minTs)) { minTs = 0; maxTs = 1; }
+  if (maxTs === minTs) maxTs = minTs + 1;
+
+  // Order rows the same way Chrome does: alphabetically by process name, then thread name -
+  // which is also why this tool's process/thread names are prefixed like "a)", "b)", "z)".
+  const rows = Array.from(rowsByKey.values());
+  rows.sort((a, b) => {
+    const pa = processNames.get(a.pid) || String(a.pid);
+    const pb = processNames.get(b.pid) || String(b.pid);
+    if (pa !== pb) return pa < pb ? -1 : 1;
+    const ta = threadNames.get(a.pid + ":" + a.tid) || String(a.tid);
+    const tb = threadNames.get(b.pid + ":" + b.tid) || String(b.tid);
+    return ta < tb ? -1 : ta > tb ? 1 : 0;
+  });
+
+  const ROW_HEIGHT = 20;
+  const NEST_INDENT = 4;
+  rows.forEach((row, idx) => {
+    row.y = idx * ROW_HEIGHT;
+    row.label = (processNames.get(row.pid) || row.pid) + " / " + (threadNames.get(row.pid + ":" + row.tid) || row.tid);
+  });
+
+  const lanesDiv = document.getElementById("lanes");
+  for (const row of rows) {
+    const div = document.createElement("div");
+    div.style.top = row.y + "px";
+    div.style.height = ROW_HEIGHT + "px";
+    div.style.lineHeight = ROW_HEIGHT + "px";
+    div.textContent = row.label;
+    div.title = row.label;
+    lanesDiv.appendChild(div);
+  }
+
+  // "cname" is one of the Catapult color_scheme.html tokens (main.rs's COLORS), not a CSS
+  // color - e.g. "thread_state_running" isn't a valid paint value, so it can't be passed
+  // straight through to an SVG "fill" attribute (an unrecognized keyword renders as black).
+  // Map each token this tool can emit to a real, distinguishable CSS color.
+  const CATAPULT_COLORS = {
+    thread_state_uninterruptible: "#556677",
+    thread_state_iowait: "#aa8822",
+    thread_state_running: "#63b598",
+    thread_state_runnable: "#8dd3c7",
+    thread_state_unknown: "#929292",
+    background_memory_dump: "#6b6b6b",
+    detailed_memory_dump: "#4f4f4f",
+    vsync_highlight_color: "#ff00ff",
+    generic_work: "#8a91b4",
+    good: "#4caf50",
+    bad: "#e53935",
+    grey: "#9e9e9e",
+    yellow: "#fdd835",
+    olive: "#808000",
+    rail_response: "#4285f4",
+    rail_animation: "#9c27b0",
+    rail_idle: "#bdbdbd",
+    rail_load: "#ff9800",
+    startup: "#00bcd4",
+    heap_dump_stack_frame: "#795548",
+    heap_dump_object_type: "#607d8b",
+    heap_dump_child_node_arrow: "#9e9d24",
+    cq_build_running: "#2196f3",
+    cq_build_passed: "#4caf50",
+    cq_build_failed: "#f44336",
+    cq_build_abandoned: "#9e9e9e",
+    cq_build_attempt_runnig: "#64b5f6",
+    cq_build_attempt_passed: "#81c784",
+    cq_build_attempt_failed: "#e57373",
+  };
+
+  function colorFor(name, explicit) {
+    if (explicit && CATAPULT_COLORS[explicit]) return CATAPULT_COLORS[explicit];
+    let hash = 0;
+    for (let i = 0; i < name.length; i++) hash = (hash * 31 + name.charCodeAt(i)) | 0;
+    return "hsl(" + (Math.abs(hash) % 360) + ", 55%, 65%)";
+  }
+
+  const svg = document.getElementById("chart");
+  const tooltip = document.getElementById("tooltip");
+  let viewStart = minTs;
+  let viewEnd = maxTs;
+
+  function showTooltip(evt, title, args) {
+    tooltip.style.display = "block";
+    tooltip.style.left = (evt.clientX + 12) + "px";
+    tooltip.style.top = (evt.clientY + 12) + "px";
+    tooltip.textContent = title + (args ? "\n" + JSON.stringify(args, null, 1) : "");
+  }
+  function hideTooltip() { tooltip.style.display = "none"; }
+
+  function render() {
+    const width = svg.clientWidth || 800;
+    const height = Math.max(svg.clientHeight || 400, rows.length * ROW_HEIGHT);
+    svg.setAttribute("height", height);
+    const span = Math.max(viewEnd - viewStart, 1e-6);
+    const xOf = (ts) => ((ts - viewStart) / span) * width;
+
+    while (svg.firstChild) svg.removeChild(svg.firstChild);
+
+    for (const row of rows) {
+      for (const s of row.spans) {
+        const x0 = xOf(s.start);
+        const x1 = xOf(s.end);
+        if (x1 < 0 || x0 > width) continue;
+        const rect = document.createElementNS("http://www.w3.org/2000/svg", "rect");
+        rect.setAttribute("x", x0);
+        rect.setAttribute("y", row.y + 2 + s.depth * NEST_INDENT);
+        rect.setAttribute("width", Math.max(x1 - x0, 1));
+        rect.setAttribute("height", Math.max(ROW_HEIGHT - 4 - s.depth * NEST_INDENT, 2));
+        rect.setAttribute("fill", colorFor(s.name, s.cname));
+        rect.setAttribute("stroke", "#0003");
+        rect.addEventListener("mousemove", (evt) => showTooltip(evt, s.name, s.args));
+        rect.addEventListener("mouseleave", hideTooltip);
+        svg.appendChild(rect);
+      }
+      for (const m of row.markers) {
+        const x = xOf(m.ts);
+        if (x < -4 || x > width + 4) continue;
+        const marker = document.createElementNS("http://www.w3.org/2000/svg", "circle");
+        marker.setAttribute("cx", x);
+        marker.setAttribute("cy", row.y + ROW_HEIGHT / 2);
+        marker.setAttribute("r", 3);
+        marker.setAttribute("fill", colorFor(m.name, m.cname));
+        marker.addEventListener("mousemove", (evt) => showTooltip(evt, m.name, m.args));
+        marker.addEventListener("mouseleave", hideTooltip);
+        svg.appendChild(marker);
+      }
+    }
+  }
+
+  svg.addEventListener("wheel", (evt) => {
+    evt.preventDefault();
+    const width = svg.clientWidth || 800;
+    const span = viewEnd - viewStart;
+    const anchorTs = viewStart + (evt.offsetX / width) * span;
+    const factor = Math.exp(evt.deltaY * 0.001);
+    viewStart = anchorTs - (anchorTs - viewStart) * factor;
+    viewEnd = anchorTs + (viewEnd - anchorTs) * factor;
+    render();
+  }, { passive: false });
+
+  let dragStartX = null;
+  let dragStartView = null;
+  svg.addEventListener("mousedown", (evt) => {
+    dragStartX = evt.clientX;
+    dragStartView = [viewStart, viewEnd];
+    svg.style.cursor = "grabbing";
+  });
+  window.addEventListener("mousemove", (evt) => {
+    if (dragStartX === null) return;
+    const width = svg.clientWidth || 800;
+    const span = dragStartView[1] - dragStartView[0];
+    const deltaTs = ((evt.clientX - dragStartX) / width) * span;
+    viewStart = dragStartView[0] - deltaTs;
+    viewEnd = dragStartView[1] - deltaTs;
+    render();
+  });
+  window.addEventListener("mouseup", () => {
+    dragStartX = null;
+    svg.style.cursor = "grab";
+  });
+  window.addEventListener("resize", render);
+
+  render();
+})();
+</script>
+</body>
+</html>
+"##;
+
+/// Writes `events` (the same processed Chrome-trace-style event list the JSON backend produces)
+/// as a single self-contained HTML file: the events are embedded as JSON, and a small bundled
+/// renderer (plain JS/SVG, no external dependencies) lays out the timeline in the browser.
+pub fn write_html_trace(events: &[Value], writer: &mut impl Write) -> io::Result<()> {
+    write!(writer, "{HTML_PREFIX}")?;
+    serde_json::to_writer(&mut *writer, events)?;
+    write!(writer, "{HTML_SUFFIX}")?;
+    Ok(())
+}
@@ -3,12 +3,17 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use clap::Parser;
+mod html;
+mod perfetto;
+
+use clap::{Parser, ValueEnum};
+use rustc_hash::FxHashMap;
+use serde::ser::{SerializeSeq, Serializer as _};
 use serde_json::{json, Map, Value};
 use std::{
     collections::{hash_map::DefaultHasher, HashMap},
     hash::{Hash, Hasher},
-    io::{BufRead, BufReader, BufWriter, Seek},
+    io::{BufRead, BufReader, BufWriter, Seek, Write},
     path::{Path, PathBuf},
 };
 use xml::{
@@ -60,15 +65,75 @@ fn hash_string(s: &str) -> u64 {
     hasher.finish()
 }
 
-// fn timestamp_to_string(timestamp) {
-//     // The python datetime object stores only microsecond precision, but the timestamps we have are in nanoseconds.
-//     // Therefore we handle the fractional seconds separately so that we don"t lose the last 3 decimal places.
-//     // Whole seconds part
-//     a = datetime.datetime.fromtimestamp(timestamp // int(1e9))
-//     // Fractional seconds
-//     b = timestamp % int(1e9)
-//     return "{}.{:09}".format(a, b)
-// }
+/// Maps the firmware's monotonic device-nanosecond counter onto wall-clock time, so that NPU
+/// traces can be correlated with host kernel/driver logs. `epoch_ns` and `device_ts` are a pair
+/// of readings taken at the same instant: a UNIX epoch timestamp (in nanoseconds) and the value
+/// of the device counter at that moment.
+#[derive(Debug, Clone)]
+struct ClockBase {
+    epoch_ns: u64,
+    device_ts: u64,
+}
+impl ClockBase {
+    /// Converts a device-counter timestamp into an absolute UNIX epoch timestamp, in nanoseconds.
+    fn to_absolute_ns(&self, device_ts: u64) -> u64 {
+        let offset = device_ts as i128 - self.device_ts as i128;
+        (self.epoch_ns as i128 + offset) as u64
+    }
+
+    /// Converts a device-counter timestamp into a full nanosecond-precision ISO-8601 string,
+    /// suitable for display alongside the (microsecond-precision) Chrome `ts` field.
+    fn to_iso8601(&self, device_ts: u64) -> String {
+        let absolute_ns = self.to_absolute_ns(device_ts);
+        // Chrome's own "ts" display only has microsecond precision, so we hand chrono the whole
+        // seconds and the nanosecond remainder separately here, keeping all 9 decimal places in
+        // the string rather than losing the last 3 to rounding.
+        let whole = (absolute_ns / 1_000_000_000) as i64;
+        let frac = (absolute_ns % 1_000_000_000) as u32;
+        chrono::DateTime::from_timestamp(whole, frac)
+            .expect("Timestamp out of range for --clock-base")
+            .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true)
+    }
+}
+
+/// Converts a device timestamp (nanoseconds, optionally rebased onto wall-clock time via
+/// `clock_base`) into the `ts` value to embed in a Chrome trace event.
+///
+/// By default this is a proper nanosecond-to-microsecond conversion to a fractional `f64`. The
+/// sub-microsecond part is kept as far as an `f64` can hold it: to the nanosecond for raw
+/// device-counter values up to about 50 days, but only to about a quarter of a microsecond for
+/// epoch-scale values rebased via `clock_base`. With `legacy_format` set, it instead reproduces
+/// the old (deliberately wrong) behaviour of dumping the raw nanosecond count straight into the
+/// microsecond field, for compatibility with older tooling that expects this file's previous,
+/// integer-only "ts" values; if `clock_base` is also given, the legacy value is the whole number
+/// of wall-clock microseconds instead.
+fn chrome_ts(device_ts: u64, clock_base: Option<&ClockBase>, legacy_format: bool) -> Value {
+    let absolute_ns = match clock_base {
+        Some(clock_base) => clock_base.to_absolute_ns(device_ts),
+        None => device_ts,
+    };
+    if legacy_format {
+        match clock_base {
+            Some(_) => (absolute_ns / 1000).into(),
+            None => absolute_ns.into(),
+        }
+    } else {
+        // As in `to_iso8601`, split into a whole part and a fractional remainder before
+        // converting to `f64`, rather than casting `absolute_ns` (an epoch-nanosecond count, so
+        // too large for `f64` to represent exactly) directly: `whole_us` is small enough
+        // (microseconds since epoch, not nanoseconds) to survive the cast exactly. The
+        // sub-microsecond remainder added to it is then rounded to the nearest `f64`.
+        let whole_us = absolute_ns / 1000;
+        let frac_ns = absolute_ns % 1000;
+        (whole_us as f64 + frac_ns as f64 / 1000.0).into()
+    }
+}
+
+/// Reads back a `ts` value produced by [`chrome_ts`], whether it was written as a whole-number
+/// (legacy) or fractional-microsecond (default) JSON number.
+fn ts_as_f64(v: &Value) -> f64 {
+    v.as_f64().unwrap()
+}
 
 /// Stores information about an agent or command XML element.
 #[derive(Clone, Debug)]
@@ -82,13 +147,23 @@ struct XmlElement {
 struct CommandList {
     commands: Vec<XmlElement>,
     current_idx_per_filter: HashMap<String, usize>,
+    occurrence_per_filter: HashMap<String, usize>,
 }
 impl CommandList {
     // We don't report the command index in the profiling data, so we have to reconstruct this.
     // We know that commands are started by the firmware in order, so we know that e.g. the second
     // START_MCE_STRIPE command we see in the profiling trace must correspond to the second START_MCE_STRIPE
     // command in the (MCE) command list. This function handles that logic.
-    fn advance(&mut self, filter_id: &str, command_name: &str) -> usize {
+    //
+    // Returns `(absolute_idx, occurrence)`. `absolute_idx` is this command's position in the
+    // shared, interleaved `commands` list, for looking up its XML. `occurrence` is the 0-based
+    // rank of this `filter_id`'s calls so far, independent of `command_name` and of how many
+    // other filters' commands sit between them in the shared list - so a stripe's Setup call
+    // (filtered on e.g. "PROGRAM_MCE_STRIPE_COMMAND") and its Execution call (filtered on
+    // "START_MCE_STRIPE_COMMAND") get the same `occurrence` for the same stripe, even though
+    // their `command_name`s (and hence absolute indices) differ. Callers that need to correlate
+    // a Setup/Execution pair (e.g. for a flow event id) must use `occurrence`, not `absolute_idx`.
+    fn advance(&mut self, filter_id: &str, command_name: &str) -> (usize, usize) {
         if !self.current_idx_per_filter.contains_key(filter_id) {
             self.current_idx_per_filter.insert(filter_id.to_string(), 0);
         }
@@ -113,7 +188,14 @@ impl CommandList {
         idx += 1;
         *self.current_idx_per_filter.get_mut(filter_id).unwrap() = idx;
 
-        result
+        let occurrence = self
+            .occurrence_per_filter
+            .entry(filter_id.to_string())
+            .or_insert(0);
+        let result_occurrence = *occurrence;
+        *occurrence += 1;
+
+        (result, result_occurrence)
     }
 }
 
@@ -253,6 +335,27 @@ fn parse_command_stream(
     )
 }
 
+/// Describes a Chrome flow event ("arrow") to be emitted alongside a timeline event, linking it
+/// to another event sharing the same `id`/`cat`. `ph` is either `"s"` (flow start, emitted by the
+/// stripe setup event) or `"f"` (flow finish, emitted by the corresponding stripe execution
+/// event).
+struct Flow {
+    id: u64,
+    cat: String,
+    ph: &'static str,
+}
+
+/// Derives the flow id linking a stripe setup event to its corresponding execution event, from
+/// the `(family, occurrence)` pair - the same `occurrence` rank already computed by
+/// `CommandList::advance`/`handle_command_and_agent` for both halves of the pair, since setup and
+/// execution commands are started by the firmware in the same relative order. This must be the
+/// per-filter occurrence rank, not the absolute index into the shared command list: setup and
+/// execution commands are interleaved with other command types in that list, so their absolute
+/// indices diverge even though they pair up 1:1.
+fn flow_id(family: &str, occurrence: usize) -> u64 {
+    hash_string(&format!("{family}:{occurrence}"))
+}
+
 /// Gets a tuple describing how this timeline entry should be displayed. The tuple contains:
 ///     - section (process) name
 ///     - row (thread) name
@@ -261,6 +364,27 @@ fn parse_command_stream(
 ///     - color
 /// Note we prefix the process/thread names with a/b/c etc. to force a specific order
 /// (as they get displayed alphabetically).
+/// Describes a Chrome flow event ("arrow") to be emitted alongside a timeline event, linking it
+/// to another event sharing the same `id`/`cat`. `ph` is either `"s"` (flow start, emitted by the
+/// stripe setup event) or `"f"` (flow finish, emitted by the corresponding stripe execution
+/// event).
+struct Flow {
+    id: u64,
+    cat: String,
+    ph: &'static str,
+}
+
+/// Derives the flow id linking a stripe setup event to its corresponding execution event, from
+/// the `(family, occurrence)` pair - the same `occurrence` rank already computed by
+/// `CommandList::advance`/`handle_command_and_agent` for both halves of the pair, since setup and
+/// execution commands are started by the firmware in the same relative order. This must be the
+/// per-filter occurrence rank, not the absolute index into the shared command list: setup and
+/// execution commands are interleaved with other command types in that list, so their absolute
+/// indices diverge even though they pair up 1:1.
+fn flow_id(family: &str, occurrence: usize) -> u64 {
+    hash_string(&format!("{family}:{occurrence}"))
+}
+
 fn process_timeline_event_start_or_instant(
     entry: &Map<String, Value>,
     agents: &[Agent],
@@ -275,15 +399,20 @@ fn process_timeline_event_start_or_instant(
     String,
     serde_json::Map<String, Value>,
     String,
+    Option<Flow>,
 ) {
     let mut args = serde_json::Map::new();
     args.insert("entry".to_string(), Value::Object(entry.clone()));
     let metadata_category = entry["metadata_category"].as_str().unwrap();
 
+    // Returns `(agent_id, agent_xml, occurrence, command_xml)`. `occurrence` is the per-filter
+    // occurrence rank from `CommandList::advance`, not the absolute command-list index, since
+    // that's what correlates a Setup call with its matching Execution call for `flow_id` (see
+    // its doc comment) - the absolute index is only used here to look up the command's own XML.
     let mut handle_command_and_agent = |command_list: &mut CommandList,
                                         command_name: &str|
      -> (usize, XmlElement, usize, XmlElement) {
-        let command_idx = command_list.advance(metadata_category, command_name);
+        let (command_idx, occurrence) = command_list.advance(metadata_category, command_name);
         let command_xml = command_list.commands[command_idx].clone();
         let agent_id: usize = command_xml
             .child_element_values
@@ -303,7 +432,7 @@ fn process_timeline_event_start_or_instant(
             agent_xml.text_representation.clone().into(),
         );
 
-        (agent_id, agent_xml, command_idx, command_xml)
+        (agent_id, agent_xml, occurrence, command_xml)
     };
 
     match metadata_category {
@@ -313,6 +442,7 @@ fn process_timeline_event_start_or_instant(
             "Inference".to_string(),
             args,
             "".to_string(),
+            None,
         ),
         "FirmwareUpdateProgress" => (
             "c) NCU MCU".to_string(),
@@ -320,6 +450,7 @@ fn process_timeline_event_start_or_instant(
             "UpdateProgress".to_string(),
             args,
             "".to_string(),
+            None,
         ),
         "FirmwareWfe" => (
             "c) NCU MCU".to_string(),
@@ -327,22 +458,30 @@ fn process_timeline_event_start_or_instant(
             "WFE".to_string(),
             args,
             "".to_string(),
+            None,
         ),
         "FirmwareDmaReadSetup" => {
-            let (agent_id, _, _, _) = handle_command_and_agent(dma_rd_commands, "DMA_COMMAND");
+            let (agent_id, _, occurrence, _) =
+                handle_command_and_agent(dma_rd_commands, "DMA_COMMAND");
             // The agent in the command stream should specify if this is weights, ple, ifm etc.
             let agent_type = agents[agent_id].xml.name.clone();
             args.insert("agent_type".to_string(), agent_type.clone().into());
+            let cat = "DmaRead".to_string();
             (
                 "c) NCU MCU".to_string(),
                 "d) DMA stripe setup".to_string(),
                 agent_type,
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                Some(Flow {
+                    id: flow_id(&cat, occurrence),
+                    cat,
+                    ph: "s",
+                }),
             )
         }
         "FirmwareDmaRead" => {
-            let (agent_id, _, _, command_xml) =
+            let (agent_id, _, occurrence, command_xml) =
                 handle_command_and_agent(dma_rd_commands, "DMA_COMMAND");
             // The agent in the command stream should specify if this is weights, ple, ifm etc.
             let agent_type = agents[agent_id].xml.name.clone();
@@ -355,26 +494,39 @@ fn process_timeline_event_start_or_instant(
             .unwrap()
                 & 0b111;
             args.insert("hardware_id".to_string(), hardware_id.into());
+            let cat = "DmaRead".to_string();
             (
                 "d) DMA".to_string(),
                 format!("a) DMA Load {}", hardware_id),
                 agent_type,
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                Some(Flow {
+                    id: flow_id(&cat, occurrence),
+                    cat,
+                    ph: "f",
+                }),
             )
         }
         "FirmwareDmaWriteSetup" => {
-            let (agent_id, _, _, _) = handle_command_and_agent(dma_wr_commands, "DMA_COMMAND");
+            let (agent_id, _, occurrence, _) =
+                handle_command_and_agent(dma_wr_commands, "DMA_COMMAND");
+            let cat = "DmaWrite".to_string();
             (
                 "c) NCU MCU".to_string(),
                 "d) DMA stripe setup".to_string(),
                 "OFM_STREAMER".to_string(),
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                Some(Flow {
+                    id: flow_id(&cat, occurrence),
+                    cat,
+                    ph: "s",
+                }),
             )
         }
         "FirmwareDmaWrite" => {
-            let (agent_id, _, _, command_xml) =
+            let (agent_id, _, occurrence, command_xml) =
                 handle_command_and_agent(dma_wr_commands, "DMA_COMMAND");
             // The hardware ID can be extracted from the command stream
             let hardware_id = u32::from_str_radix(
@@ -384,61 +536,91 @@ fn process_timeline_event_start_or_instant(
             .unwrap()
                 & 0b111;
             args.insert("hardware_id".to_string(), hardware_id.into());
+            let cat = "DmaWrite".to_string();
             (
                 "d) DMA".to_string(),
                 format!("a) DMA Save {}", hardware_id),
                 "OFM_STREAMER".to_string(),
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                Some(Flow {
+                    id: flow_id(&cat, occurrence),
+                    cat,
+                    ph: "f",
+                }),
             )
         }
         "FirmwareMceStripeSetup" => {
-            let (agent_id, _, _, _) =
+            let (agent_id, _, occurrence, _) =
                 handle_command_and_agent(mce_commands, "PROGRAM_MCE_STRIPE_COMMAND");
+            let cat = "MceStripe".to_string();
             (
                 "c) NCU MCU".to_string(),
                 "c) MCE stripe setup".to_string(),
                 "MCE stripe setup".to_string(),
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                Some(Flow {
+                    id: flow_id(&cat, occurrence),
+                    cat,
+                    ph: "s",
+                }),
             )
         }
         "FirmwareMceStripe" => {
-            let (agent_id, agent_xml, _, _) =
+            let (agent_id, agent_xml, occurrence, _) =
                 handle_command_and_agent(mce_commands, "START_MCE_STRIPE_COMMAND");
             *mce_bank = (*mce_bank + 1) % 2;
             // Get operation (depthwise vs. conv etc.) from the command stream
             let operation = agent_xml.child_element_values.get("MCE_OP_MODE").unwrap();
+            let cat = "MceStripe".to_string();
             (
                 "f) MCE".to_string(),
                 format!("a) MCE bank {}", mce_bank),
                 format!("{}", operation),
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                Some(Flow {
+                    id: flow_id(&cat, occurrence),
+                    cat,
+                    ph: "f",
+                }),
             )
         }
         "FirmwarePleStripeSetup" => {
-            let (agent_id, _, _, _) =
+            let (agent_id, _, occurrence, _) =
                 handle_command_and_agent(ple_commands, "START_PLE_STRIPE_COMMAND");
+            let cat = "PleStripe".to_string();
             (
                 "c) NCU MCU".to_string(),
                 "c) PLE stripe setup".to_string(),
                 format!("PLE stripe setup"),
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                Some(Flow {
+                    id: flow_id(&cat, occurrence),
+                    cat,
+                    ph: "s",
+                }),
             )
         }
         "FirmwarePleStripe" => {
-            let (agent_id, agent_xml, _, _) =
+            let (agent_id, agent_xml, occurrence, _) =
                 handle_command_and_agent(ple_commands, "START_PLE_STRIPE_COMMAND");
             // Get PLE kernel ID from the command stream
             let ple_kernel_id = agent_xml.child_element_values.get("PLE_KERNEL_ID").unwrap();
+            let cat = "PleStripe".to_string();
             (
                 "g) PLE".to_string(),
                 "a) PLE".to_string(),
                 format!("{}", ple_kernel_id),
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                Some(Flow {
+                    id: flow_id(&cat, occurrence),
+                    cat,
+                    ph: "f",
+                }),
             )
         }
         "FirmwareUdma" => {
@@ -450,6 +632,7 @@ fn process_timeline_event_start_or_instant(
                 format!("UDMA"),
                 args,
                 COLORS[agent_id % COLORS.len()].to_string(),
+                None,
             )
         }
         "FirmwareLabel" => {
@@ -461,6 +644,7 @@ fn process_timeline_event_start_or_instant(
                 label,
                 args,
                 COLORS[0].to_string(),
+                None,
             )
         }
         "InferenceLifetime" => (
@@ -469,6 +653,7 @@ fn process_timeline_event_start_or_instant(
             format!("Inference"),
             args,
             "".to_string(),
+            None,
         ),
         "BufferLifetime" => (
             "a) Driver Library".to_string(),
@@ -476,6 +661,7 @@ fn process_timeline_event_start_or_instant(
             format!("Buffer {}", entry["id"]),
             args,
             "".to_string(),
+            None,
         ),
         x => {
             panic!("Unknown metadata category: {x}");
@@ -537,14 +723,17 @@ fn process_counter_entry(
 //     [begin_event, end_event]
 // }
 
-fn process_finalize(
-    mut data: Vec<Value>,
+/// Builds the begin/end slice events which show the start and end of each agent, and registers
+/// the "b) Command Stream" process and its per-agent threads in `process_names`/`thread_names`.
+fn build_agent_events(
     agents: &[Agent],
-    process_names: &mut HashMap<u64, String>,
-    thread_names: &mut HashMap<(u64, u64), String>,
-    add_timeline_bars: bool,
+    process_names: &mut FxHashMap<u64, String>,
+    thread_names: &mut FxHashMap<(u64, u64), String>,
+    clock_base: Option<&ClockBase>,
+    legacy_format: bool,
 ) -> Vec<Value> {
-    // Add events to show the start and end of each agent
+    let mut events = vec![];
+
     let process_name = "b) Command Stream".to_string();
     let pid = hash_string(&process_name);
     process_names.insert(pid, process_name);
@@ -562,27 +751,48 @@ fn process_finalize(
         let begin_event = json!({
             "name": format!("Agent {} ({})", agent_idx, agent.xml.name),
             "ph": "B",
-            "ts": agent.start_timestamp.unwrap(),
+            "ts": chrome_ts(agent.start_timestamp.unwrap(), clock_base, legacy_format),
             "pid": pid,
             "tid": tid,
             "args": { "agent_xml": agents[agent_idx].xml.text_representation },
         });
-        data.push(begin_event.clone());
+        events.push(begin_event.clone());
 
         let mut end_event = begin_event.clone();
         end_event["ph"] = "E".into();
-        end_event["ts"] = agent.end_timestamp.unwrap().into();
-        data.push(end_event);
+        end_event["ts"] = chrome_ts(agent.end_timestamp.unwrap(), clock_base, legacy_format);
+        events.push(end_event);
     }
 
+    events
+}
+
+fn process_finalize(
+    mut data: Vec<Value>,
+    agents: &[Agent],
+    process_names: &mut FxHashMap<u64, String>,
+    thread_names: &mut FxHashMap<(u64, u64), String>,
+    add_timeline_bars: bool,
+    clock_base: Option<&ClockBase>,
+    legacy_format: bool,
+) -> Vec<Value> {
+    // Add events to show the start and end of each agent
+    data.extend(build_agent_events(
+        agents,
+        process_names,
+        thread_names,
+        clock_base,
+        legacy_format,
+    ));
+
     // Add a fake "End" event for any timeline events which we didn"t find an end for. This might be because
     // for example a buffer was still alive when the profiling data was dumped. If we don"t add an end event ourselves,
     // Chrome displays these more like an instantaneous event, which can be confusing.
     // Also do some validation
     let max_timestamp = data
         .iter()
-        .map(|e| e.as_object().unwrap().get("ts").unwrap().as_u64().unwrap())
-        .max();
+        .map(|e| ts_as_f64(e.as_object().unwrap().get("ts").unwrap()))
+        .fold(0.0, f64::max);
     let mut begin_events = HashMap::<(u64, u64), Value>::new();
     for entry in &data {
         let key = (
@@ -601,9 +811,7 @@ fn process_finalize(
         } else if entry["ph"] == "E" {
             if begin_events.contains_key(&key) {
                 // Check timestamp for end event is after begin
-                if entry["ts"].as_u64().unwrap()
-                    < begin_events.get(&key).unwrap()["ts"].as_u64().unwrap()
-                {
+                if ts_as_f64(&entry["ts"]) < ts_as_f64(&begin_events.get(&key).unwrap()["ts"]) {
                     panic!("End event ({}) timestamp is before beginning ({})! Chrome won't display this", entry, begin_events.get(&key).unwrap());
                 }
 
@@ -621,7 +829,11 @@ fn process_finalize(
             (begin_event["name"].as_str().unwrap().to_string() + " (NOT ENDED)").into();
         let mut end_event = begin_event.clone();
         end_event["ph"] = "E".into();
-        end_event["ts"] = max_timestamp.into();
+        end_event["ts"] = if legacy_format {
+            (max_timestamp as u64).into()
+        } else {
+            max_timestamp.into()
+        };
         data.push(end_event);
     }
 
@@ -683,31 +895,103 @@ fn process_finalize(
     return data;
 }
 
+/// The driver library's profiling-dump JSON layout has changed over time; each schema version
+/// implements this trait to pull the entry kind, device timestamp and start/end pairing id out
+/// of one raw entry object, however that particular version happens to spell them. The rest of
+/// this tool (`process_entry` onwards) only ever sees the result, so it stays oblivious to which
+/// schema produced it.
+trait SchemaDecoder {
+    /// One of "TimelineEventStart", "TimelineEventEnd", "TimelineEventInstant", "CounterSample".
+    fn entry_type<'a>(&self, entry: &'a Map<String, Value>) -> &'a str;
+    /// Device timestamp, in nanoseconds.
+    fn timestamp(&self, entry: &Map<String, Value>) -> u64;
+    /// The id used to pair a `TimelineEventStart` with its later `TimelineEventEnd`.
+    fn event_id(&self, entry: &Map<String, Value>) -> u64;
+}
+
+/// Schema version 1: the original (and so far only) profiling-dump layout, with `"type"`,
+/// `"timestamp"` and `"id"` fields directly on each entry. A bare top-level JSON array of entries
+/// with no `"schema_version"` field predates versioning entirely and is also version 1.
+struct SchemaV1;
+
+impl SchemaDecoder for SchemaV1 {
+    fn entry_type<'a>(&self, entry: &'a Map<String, Value>) -> &'a str {
+        entry["type"].as_str().unwrap()
+    }
+
+    fn timestamp(&self, entry: &Map<String, Value>) -> u64 {
+        entry["timestamp"].as_u64().unwrap()
+    }
+
+    fn event_id(&self, entry: &Map<String, Value>) -> u64 {
+        entry["id"].as_u64().unwrap()
+    }
+}
+
+/// Returns the decoder for `version`, or gives a clear error naming the unsupported version
+/// rather than letting an unfamiliar schema reach `process_entry` and panic on a missing field.
+fn decoder_for_schema_version(version: u64) -> Box<dyn SchemaDecoder> {
+    match version {
+        1 => Box::new(SchemaV1),
+        other => panic!(
+            "Unsupported profiling-dump schema version {other}: this build of the converter \
+             only understands version 1. Update profiling_converter to add a decoder for this \
+             schema version."
+        ),
+    }
+}
+
+/// Reads the dump's schema version: an explicit top-level `"schema_version"` integer if the
+/// input is wrapped as `{"schema_version": N, "entries": [...]}`, or implicitly version 1 if the
+/// input is the original bare array of entries (which predates this field existing at all).
+fn schema_version(input_json: &Value) -> u64 {
+    match input_json.get("schema_version") {
+        Some(version) => version.as_u64().expect("\"schema_version\" must be an integer"),
+        None => 1,
+    }
+}
+
+/// Returns the entries to convert, regardless of whether the input is the original bare array or
+/// the newer `{"schema_version": ..., "entries": [...]}` wrapper.
+fn schema_entries(input_json: &Value) -> &[Value] {
+    match input_json.get("entries") {
+        Some(entries) => entries.as_array().expect("\"entries\" must be an array"),
+        None => input_json.as_array().expect("Invalid json"),
+    }
+}
+
 fn process_entry(
     entry: &Map<String, Value>,
-    in_progress_events: &mut HashMap<u64, (u64, u64, Option<usize>)>,
-    process_names: &mut HashMap<u64, String>,
-    thread_names: &mut HashMap<(u64, u64), String>,
+    decoder: &dyn SchemaDecoder,
+    in_progress_events: &mut FxHashMap<u64, (u64, u64, Option<usize>)>,
+    process_names: &mut FxHashMap<u64, String>,
+    thread_names: &mut FxHashMap<(u64, u64), String>,
     agents: &mut [Agent],
     dma_rd_commands: &mut CommandList,
     dma_wr_commands: &mut CommandList,
     mce_commands: &mut CommandList,
     ple_commands: &mut CommandList,
     mce_bank: &mut u32,
-) -> Value {
-    let entry_type = entry["type"].as_str().unwrap();
-    let timestamp = entry["timestamp"].as_u64().unwrap();
-    // Timestamps from the driver library"s json dump are in nanoseconds, but the Chrome Trace View format uses
-    // microseconds. It only support whole numbers though, so we DELIBERATELY DONT convert correctly here so that
-    // we don"t lose precision. The downside of this is that the timeline in Chrome will show everything taking
-    // 1000x longer than it did in reality.
-    let chrome_ts = timestamp;
+    clock_base: Option<&ClockBase>,
+    legacy_format: bool,
+) -> Vec<Value> {
+    let entry_type = decoder.entry_type(entry);
+    let timestamp = decoder.timestamp(entry);
+    // Timestamps from the driver library"s json dump are in nanoseconds, but the Chrome Trace View
+    // format uses microseconds, so we convert here, keeping the fractional part (to the precision
+    // described on `chrome_ts`). (With --legacy-format, we instead reproduce the old, deliberately
+    // wrong behaviour of dumping the raw nanosecond count straight into the microsecond field, or
+    // whole microseconds with --clock-base, for tooling that still expects this file's previous
+    // whole-number-only "ts" values.)
+    // If a --clock-base has been given, we know the real wall-clock time so we rebase onto that
+    // (we also attach a full nanosecond-precision ISO-8601 string into "args" for display, since
+    // the "ts" field itself is only microsecond-precision).
+    let chrome_ts_value = chrome_ts(timestamp, clock_base, legacy_format);
+    let wall_clock = clock_base.map(|clock_base| clock_base.to_iso8601(timestamp));
 
     if entry_type == "TimelineEventEnd" {
         // All we need is the PID and TID from the start event
-        let (pid, tid, agent_id) = in_progress_events
-            .get(&entry["id"].as_u64().unwrap())
-            .unwrap();
+        let (pid, tid, agent_id) = in_progress_events.get(&decoder.event_id(entry)).unwrap();
 
         // Update agent lifetime
         if let Some(agent_id) = agent_id {
@@ -717,16 +1001,22 @@ fn process_entry(
             ));
         }
 
-        return json!({
+        let mut result = json!({
             "ph": "E",
-            "ts": chrome_ts,
+            "ts": chrome_ts_value.clone(),
             "pid": pid,
             "tid": tid,
         });
+        if let Some(wall_clock) = wall_clock {
+            result["args"] = json!({ "wall_clock": wall_clock });
+        }
+        return vec![result];
     }
 
-    let (ph, process_name, thread_name, name, args, color) = if entry_type == "TimelineEventStart" {
-        let (process_name, thread_name, name, args, color) =
+    let (ph, process_name, thread_name, name, args, color, flow) = if entry_type
+        == "TimelineEventStart"
+    {
+        let (process_name, thread_name, name, args, color, flow) =
             process_timeline_event_start_or_instant(
                 entry,
                 agents,
@@ -737,9 +1027,9 @@ fn process_entry(
                 mce_bank,
             );
 
-        ("B", process_name, thread_name, name, args, color)
+        ("B", process_name, thread_name, name, args, color, flow)
     } else if entry_type == "TimelineEventInstant" {
-        let (process_name, thread_name, name, args, color) =
+        let (process_name, thread_name, name, args, color, flow) =
             process_timeline_event_start_or_instant(
                 entry,
                 agents,
@@ -750,14 +1040,19 @@ fn process_entry(
                 mce_bank,
             );
 
-        ("I", process_name, thread_name, name, args, color)
+        ("I", process_name, thread_name, name, args, color, flow)
     } else if entry_type == "CounterSample" {
         let (process_name, thread_name, name, args, color) = process_counter_entry(entry);
-        ("C", process_name, thread_name, name, args, color)
+        ("C", process_name, thread_name, name, args, color, None)
     } else {
         panic!("Unknown entry_type ({})", entry_type)
     };
 
+    let mut args = args;
+    if let Some(wall_clock) = wall_clock {
+        args.insert("wall_clock".to_string(), wall_clock.into());
+    }
+
     // Each section (process) needs a unique numerical ID.
     let pid = hash_string(&process_name);
     // Store the mapping from process ID to name, so we can list it in the JSON file at the end
@@ -770,9 +1065,9 @@ fn process_entry(
 
     // Construct the JSON object
     let result = json!({
-        "name": name,
+        "name": name.clone(),
         "ph": ph,
-        "ts": chrome_ts,
+        "ts": chrome_ts_value.clone(),
         "pid": pid,
         "tid": tid,
         "args": args,
@@ -790,37 +1085,62 @@ fn process_entry(
             agent_id_maybe = Some(agent_id);
         }
 
-        in_progress_events.insert(entry["id"].as_u64().unwrap(), (pid, tid, agent_id_maybe));
+        in_progress_events.insert(decoder.event_id(entry), (pid, tid, agent_id_maybe));
     }
 
-    result
+    let mut results = vec![result];
+
+    // Emit a flow event ("arrow") linking this event to the corresponding stripe setup/execution
+    // event, so that pipeline dependencies and stalls between the NCU and the compute engines are
+    // directly visible.
+    if let Some(flow) = flow {
+        results.push(json!({
+            "name": name,
+            "cat": flow.cat,
+            "ph": flow.ph,
+            "id": flow.id,
+            "ts": chrome_ts_value.clone(),
+            "pid": pid,
+            "tid": tid,
+        }));
+    }
+
+    results
 }
 
+/// Converts `entries` into the full, buffered `Vec<Value>` event list. Used only for the
+/// post-processing passes that genuinely need the whole trace in memory at once - merging
+/// multiple `--source` inputs, `--collapse-repeats`, `--compact`, and `--format perfetto`/`html` -
+/// since `main` otherwise prefers the constant-memory `process_json_streaming`. See `can_stream`.
 fn process_json(
     entries: &[Value],
+    decoder: &dyn SchemaDecoder,
     agents: &mut [Agent],
     dma_rd_commands: &mut CommandList,
     dma_wr_commands: &mut CommandList,
     mce_commands: &mut CommandList,
     ple_commands: &mut CommandList,
     add_timeline_bars: bool,
+    clock_base: Option<&ClockBase>,
+    legacy_format: bool,
 ) -> Vec<Value> {
     // This will be built up with the list of trace objects to save to the JSON file
     let mut result = vec![];
     // This will be built up with a map from process IDs to names and then added to the end of the JSON file.
-    let mut process_names = HashMap::new();
+    let mut process_names = FxHashMap::default();
     // Will be built up with a map of (process ID, thread ID) to thread names and then added to the end of the JSON file
-    let mut thread_names = HashMap::new();
+    let mut thread_names = FxHashMap::default();
 
-    let mut in_progress_events = HashMap::new();
+    let mut in_progress_events = FxHashMap::default();
 
     let mut mce_bank = 1;
 
     // Convert each line that has an entry
     for entry in entries {
         // Extract the fields for the entry
-        let output_json_object = process_entry(
+        let output_json_objects = process_entry(
             entry.as_object().unwrap(),
+            decoder,
             &mut in_progress_events,
             &mut process_names,
             &mut thread_names,
@@ -830,9 +1150,11 @@ fn process_json(
             mce_commands,
             ple_commands,
             &mut mce_bank,
+            clock_base,
+            legacy_format,
         );
 
-        result.push(output_json_object);
+        result.extend(output_json_objects);
     }
 
     process_finalize(
@@ -841,9 +1163,156 @@ fn process_json(
         &mut process_names,
         &mut thread_names,
         add_timeline_bars,
+        clock_base,
+        legacy_format,
     )
 }
 
+/// Same conversion as `process_json`, but writes each event to `writer` as soon as it is
+/// produced instead of buffering the whole output trace in a `Vec<Value>`. The state kept for the
+/// output is roughly proportional to the number of currently-open slices rather than the total
+/// event count. The input `entries` are still an already-parsed, in-memory slice, so peak memory
+/// still grows with the size of the input capture.
+///
+/// Chrome's JSON Array trace format tolerates a file which opens with `[` and contains
+/// comma-separated objects with no closing `]`, so we never need to go back and patch up
+/// anything we've already flushed.
+fn process_json_streaming(
+    entries: &[Value],
+    decoder: &dyn SchemaDecoder,
+    agents: &mut [Agent],
+    dma_rd_commands: &mut CommandList,
+    dma_wr_commands: &mut CommandList,
+    mce_commands: &mut CommandList,
+    ple_commands: &mut CommandList,
+    writer: &mut impl Write,
+    clock_base: Option<&ClockBase>,
+    legacy_format: bool,
+) {
+    let mut process_names = FxHashMap::default();
+    let mut thread_names = FxHashMap::default();
+    let mut in_progress_events = FxHashMap::default();
+    let mut mce_bank = 1;
+
+    // Currently-open timeline slices, keyed by (pid, tid), so we can synthesize "(NOT ENDED)"
+    // closers for anything still open once we reach EOF. This stays small (bounded by the
+    // number of concurrently-open slices) rather than growing with the whole trace.
+    let mut open_slices: HashMap<(u64, u64), (f64, String)> = HashMap::new();
+    let mut max_timestamp: f64 = 0.0;
+
+    if !legacy_format {
+        write!(writer, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":")
+            .expect("Failed to write output JSON file");
+    }
+    let mut serializer = serde_json::Serializer::new(&mut *writer);
+    let mut events_seq = serializer
+        .serialize_seq(None)
+        .expect("Failed to write output JSON file");
+
+    for entry in entries {
+        let events = process_entry(
+            entry.as_object().unwrap(),
+            decoder,
+            &mut in_progress_events,
+            &mut process_names,
+            &mut thread_names,
+            agents,
+            dma_rd_commands,
+            dma_wr_commands,
+            mce_commands,
+            ple_commands,
+            &mut mce_bank,
+            clock_base,
+            legacy_format,
+        );
+
+        for event in &events {
+            let pid = event["pid"].as_u64().unwrap();
+            let tid = event["tid"].as_u64().unwrap();
+            let ts = ts_as_f64(&event["ts"]);
+            max_timestamp = f64::max(max_timestamp, ts);
+
+            match event["ph"].as_str().unwrap() {
+                "B" => {
+                    let name = event["name"].as_str().unwrap().to_string();
+                    if open_slices.contains_key(&(pid, tid)) {
+                        println!("Warning: Begin event twice in a row before an End: {event}");
+                    } else {
+                        open_slices.insert((pid, tid), (ts, name));
+                    }
+                }
+                "E" => {
+                    if open_slices.remove(&(pid, tid)).is_none() {
+                        println!("Warning: End event does not have corresponding Begin: {event}");
+                    }
+                }
+                _ => (),
+            }
+
+            events_seq
+                .serialize_element(event)
+                .expect("Failed to write output JSON file");
+        }
+    }
+
+    // Flush the begin/end slices for each agent now that the whole command stream has been
+    // consumed and every agent's start/end timestamp is known.
+    for event in build_agent_events(
+        agents,
+        &mut process_names,
+        &mut thread_names,
+        clock_base,
+        legacy_format,
+    ) {
+        events_seq
+            .serialize_element(&event)
+            .expect("Failed to write output JSON file");
+    }
+
+    // Synthesize a closer for anything still open at EOF, using the last timestamp we saw
+    // rather than scanning back over every event.
+    for ((pid, tid), (_begin_ts, name)) in open_slices {
+        let end_event = json!({
+            "name": format!("{name} (NOT ENDED)"),
+            "ph": "E",
+            "ts": if legacy_format { (max_timestamp as u64).into() } else { Value::from(max_timestamp) },
+            "pid": pid,
+            "tid": tid,
+        });
+        events_seq
+            .serialize_element(&end_event)
+            .expect("Failed to write output JSON file");
+    }
+
+    // Append the metadata which gives a name to each process and thread.
+    for (pid, name) in &process_names {
+        let metadata = json!({
+            "name": "process_name", "ph": "M", "pid": pid, "args": {"name": name}
+        });
+        events_seq
+            .serialize_element(&metadata)
+            .expect("Failed to write output JSON file");
+    }
+    for ((pid, tid), name) in &thread_names {
+        let metadata = json!({
+            "name": "thread_name", "ph": "M", "pid": pid, "tid": tid, "args": {"name": name}
+        });
+        events_seq
+            .serialize_element(&metadata)
+            .expect("Failed to write output JSON file");
+    }
+
+    if !legacy_format {
+        events_seq.end().expect("Failed to write output JSON file");
+        write!(writer, "}}").expect("Failed to write output JSON file");
+    }
+    // With --legacy-format, `events_seq` is intentionally left unfinished here rather than
+    // calling `.end()`: Chrome's JSON Array trace format tolerates a file which opens with `[`
+    // and contains comma-separated objects with no closing `]`, so we never need to go back and
+    // patch up anything we've already flushed. Kept only for tooling still relying on this
+    // file's previous (also never-closed) shape.
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -860,43 +1329,452 @@ struct Args {
     output: PathBuf,
 
     /// Add bars displayed at the top of the trace which show a timeline.
-    /// Because the timestamps in the produced trace file are not displayed correctly in Chrome,
-    /// the built-in timeline is confusing so this adds an alternative.
+    /// With --legacy-format, the timestamps in the produced trace file are not displayed
+    /// correctly in Chrome, so the built-in timeline is confusing and this adds an alternative.
     /// These bars can also be useful for zooming to a fixed scale, for comparing two traces
     #[arg(short, long, default_value_t = false)]
     add_timeline_bars: bool,
+
+    /// Write the output in this tool's old format: a bare JSON array of trace events (rather
+    /// than the `{"traceEvents": [...], "displayTimeUnit": "ns"}` object Chrome's trace viewer
+    /// itself produces), with "ts" left as the previous whole-number-only, not-really-in-
+    /// microseconds value (whole wall-clock microseconds when --clock-base is given). Only needed
+    /// for compatibility with scripts written against that shape.
+    #[arg(long, default_value_t = false)]
+    legacy_format: bool,
+
+    /// The converter already streams events straight to the output file, rather than buffering
+    /// the whole trace in memory, whenever the rest of the flags allow it (a single source,
+    /// --format json, and neither --compact nor --collapse-repeats, all of which need the full
+    /// event list up-front). Pass this to turn an incompatible combination into an immediate,
+    /// clear error instead of silently falling back to buffering - useful for a large capture,
+    /// where that fallback would also hold the whole converted trace in memory. The input file
+    /// itself is parsed fully into memory either way.
+    #[arg(long, default_value_t = false)]
+    streaming: bool,
+
+    /// A pair of readings, taken at the same instant, mapping the firmware's monotonic
+    /// device-nanosecond counter onto wall-clock time: "<unix_epoch_ns>:<device_counter_ns>".
+    /// When given, event timestamps are converted to absolute wall-clock time (so the trace
+    /// can be correlated with host kernel/driver logs) instead of the raw device counter.
+    #[arg(long, value_parser = parse_clock_base)]
+    clock_base: Option<ClockBase>,
+
+    /// An additional (command stream XML, profiling JSON) source to merge into a single,
+    /// time-interleaved trace, given as "<command_stream.xml>,<profiling.json>". Repeat this
+    /// flag once per NPU core / capture to merge together; when given, --command-stream and
+    /// --profiling-entries are ignored. Each source's processes are tagged in the output so
+    /// that concurrent cores or back-to-back captures can still be told apart.
+    #[arg(long = "source", value_parser = parse_source)]
+    sources: Vec<Source>,
+
+    /// Output format. "perfetto" writes the compact Perfetto protobuf trace format instead of
+    /// Chrome JSON, interning each distinct agent/command XML blob once instead of repeating it
+    /// in every event's args. "html" writes a single self-contained HTML report with a bundled
+    /// timeline viewer, for sharing or viewing without an external trace viewer. Not supported
+    /// together with --streaming.
+    #[arg(long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+
+    /// Shrink the output to help stay under Chrome trace viewer's ~256MB single-string limit:
+    /// process/thread IDs become small sequential integers instead of full 64-bit hashes, every
+    /// timestamp is rebased so the earliest event starts near zero, "ts"/"dur" are rounded to 3
+    /// significant digits, and the "cname"/"args" fields (not needed to render the timeline) are
+    /// dropped. Not supported together with --streaming or --format perfetto.
+    #[arg(long, default_value_t = false)]
+    compact: bool,
+
+    /// Collapse runs of identical adjacent CounterSample/TimelineEventInstant samples on the same
+    /// thread into a single begin/end duration event spanning the run, instead of emitting every
+    /// individual sample. Drastically reduces event count for steady-state counters, at the cost
+    /// of no longer showing every sample - this changes the on-screen shape of the timeline, so
+    /// it's opt-in. Not supported together with --streaming.
+    #[arg(long, default_value_t = false)]
+    collapse_repeats: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    Perfetto,
+    Html,
+}
+
+fn parse_clock_base(s: &str) -> Result<ClockBase, String> {
+    let (epoch_ns, device_ts) = s
+        .split_once(':')
+        .ok_or("Expected format <unix_epoch_ns>:<device_counter_ns>")?;
+    Ok(ClockBase {
+        epoch_ns: epoch_ns.parse().map_err(|e| format!("Invalid epoch_ns: {e}"))?,
+        device_ts: device_ts.parse().map_err(|e| format!("Invalid device_counter_ns: {e}"))?,
+    })
+}
+
+/// A single (command stream XML, profiling JSON) pair - one NPU core or capture - to be merged
+/// into the output trace. See `--source`.
+#[derive(Debug, Clone)]
+struct Source {
+    command_stream: PathBuf,
+    profiling_entries: PathBuf,
+}
+
+fn parse_source(s: &str) -> Result<Source, String> {
+    let (command_stream, profiling_entries) = s
+        .split_once(',')
+        .ok_or("Expected format <command_stream.xml>,<profiling.json>")?;
+    Ok(Source {
+        command_stream: PathBuf::from(command_stream),
+        profiling_entries: PathBuf::from(profiling_entries),
+    })
+}
+
+/// Converts all the events produced for a single source of a merged, multi-source trace so that
+/// they can be combined with the events from other sources without colliding: process IDs are
+/// remapped to new (still deterministic) values unique to this source, each process's display
+/// name is prefixed with `tag` so that e.g. concurrent cores can be told apart, and flow event
+/// ("s"/"f") ids are likewise remapped, since `flow_id` derives from a per-source occurrence rank
+/// that resets to 0 for every source and would otherwise draw arrows between unrelated sources.
+fn apply_source_tag(events: &mut [Value], tag: &str) {
+    let mut pid_remap: HashMap<u64, u64> = HashMap::new();
+    let mut flow_id_remap: HashMap<u64, u64> = HashMap::new();
+    for event in events.iter() {
+        if let Some(pid) = event.get("pid").and_then(Value::as_u64) {
+            pid_remap
+                .entry(pid)
+                .or_insert_with(|| hash_string(&format!("{tag}{pid}")));
+        }
+        if (event["ph"] == "s" || event["ph"] == "f") && event["id"].is_u64() {
+            let id = event["id"].as_u64().unwrap();
+            flow_id_remap
+                .entry(id)
+                .or_insert_with(|| hash_string(&format!("{tag}{id}")));
+        }
+    }
+
+    for event in events.iter_mut() {
+        if let Some(pid) = event["pid"].as_u64() {
+            event["pid"] = pid_remap[&pid].into();
+        }
+        if event["ph"] == "M" && event["name"] == "process_name" {
+            let name = event["args"]["name"].as_str().unwrap().to_string();
+            event["args"]["name"] = format!("{tag}{name}").into();
+        }
+        if (event["ph"] == "s" || event["ph"] == "f") && event["id"].is_u64() {
+            let id = event["id"].as_u64().unwrap();
+            event["id"] = flow_id_remap[&id].into();
+        }
+    }
+}
+
+/// Rounds `x` to 3 significant digits.
+fn round_sig3(x: f64) -> f64 {
+    if x == 0.0 || !x.is_finite() {
+        return x;
+    }
+    let magnitude = x.abs().log10().floor();
+    let factor = 10f64.powi(2 - magnitude as i32);
+    (x * factor).round() / factor
+}
+
+/// Shrinks a fully-built event list to help stay under Chrome trace viewer's ~256MB
+/// single-string limit. See `--compact`.
+fn compact_events(mut events: Vec<Value>) -> Vec<Value> {
+    let oldest_ts = events
+        .iter()
+        .filter_map(|e| e.get("ts").and_then(Value::as_f64))
+        .fold(f64::INFINITY, f64::min);
+    let oldest_ts = if oldest_ts.is_finite() { oldest_ts } else { 0.0 };
+
+    let mut pid_remap: HashMap<u64, u32> = HashMap::new();
+    let mut tid_remap: HashMap<(u64, u64), u32> = HashMap::new();
+
+    for event in &mut events {
+        if let Some(pid) = event.get("pid").and_then(Value::as_u64) {
+            let next_pid = pid_remap.len() as u32;
+            let short_pid = *pid_remap.entry(pid).or_insert(next_pid);
+
+            if let Some(tid) = event.get("tid").and_then(Value::as_u64) {
+                let next_tid = tid_remap.len() as u32;
+                let short_tid = *tid_remap.entry((pid, tid)).or_insert(next_tid);
+                event["tid"] = short_tid.into();
+            }
+
+            // Remap "pid" after "tid", since the latter is keyed by the original "pid".
+            event["pid"] = short_pid.into();
+        }
+
+        if let Some(ts) = event.get("ts").and_then(Value::as_f64) {
+            event["ts"] = round_sig3(ts - oldest_ts).into();
+        }
+        if let Some(dur) = event.get("dur").and_then(Value::as_f64) {
+            event["dur"] = round_sig3(dur).into();
+        }
+
+        if event["ph"] != "M" {
+            if let Some(obj) = event.as_object_mut() {
+                obj.remove("cname");
+                obj.remove("args");
+            }
+        }
+    }
+
+    events
+}
+
+/// A run of identical adjacent "C"/"I" samples on the same (pid, tid), not yet flushed.
+struct OpenSampleRun {
+    name: Value,
+    args: Value,
+    cname: Value,
+    pid: Value,
+    tid: Value,
+    start_ts: Value,
+    last_ts: Value,
+}
+
+/// Returns `args` with the "entry"/"wall_clock" keys removed, if present, so two samples can be
+/// compared for equality without their own unique per-sample device timestamp (embedded in the
+/// raw "entry") or wall-clock string making otherwise-identical samples compare unequal.
+fn comparable_sample_args(args: &Value) -> Value {
+    let mut args = args.clone();
+    if let Some(obj) = args.as_object_mut() {
+        obj.remove("entry");
+        obj.remove("wall_clock");
+    }
+    args
+}
+
+/// Turns an `OpenSampleRun` into the begin/end duration event pair it collapses to.
+fn flush_sample_run(run: OpenSampleRun, result: &mut Vec<Value>) {
+    let begin_event = json!({
+        "name": run.name,
+        "ph": "B",
+        "ts": run.start_ts,
+        "pid": run.pid,
+        "tid": run.tid,
+        "args": run.args,
+        "cname": run.cname,
+    });
+    let mut end_event = begin_event.clone();
+    end_event["ph"] = "E".into();
+    end_event["ts"] = run.last_ts;
+    result.push(begin_event);
+    result.push(end_event);
+}
+
+/// Collapses runs of identical adjacent `CounterSample`/`TimelineEventInstant` samples ("C"/"I"
+/// events) on the same (pid, tid) into a single begin/end ("B"/"E") duration event spanning the
+/// run, instead of emitting every individual sample. See `--collapse-repeats`.
+fn collapse_repeated_samples(events: Vec<Value>) -> Vec<Value> {
+    let mut open_runs: FxHashMap<(u64, u64), OpenSampleRun> = FxHashMap::default();
+    let mut result = Vec::with_capacity(events.len());
+
+    for event in events {
+        let ph = event["ph"].as_str().unwrap_or("");
+        if ph != "C" && ph != "I" {
+            result.push(event);
+            continue;
+        }
+
+        let key = (
+            event["pid"].as_u64().unwrap(),
+            event["tid"].as_u64().unwrap(),
+        );
+
+        // Compare on "args" with the per-sample "entry"/"wall_clock" keys stripped out: the raw
+        // "entry" blob embeds that sample's own unique device timestamp (and possibly an "id"),
+        // and "wall_clock" is likewise unique per sample, so leaving either in would make every
+        // adjacent pair of otherwise-identical samples compare unequal and never collapse.
+        let matches = open_runs.get(&key).is_some_and(|run| {
+            run.name == event["name"]
+                && comparable_sample_args(&run.args) == comparable_sample_args(&event["args"])
+        });
+
+        if matches {
+            open_runs.get_mut(&key).unwrap().last_ts = event["ts"].clone();
+        } else {
+            if let Some(run) = open_runs.remove(&key) {
+                flush_sample_run(run, &mut result);
+            }
+            open_runs.insert(
+                key,
+                OpenSampleRun {
+                    name: event["name"].clone(),
+                    args: event["args"].clone(),
+                    cname: event["cname"].clone(),
+                    pid: event["pid"].clone(),
+                    tid: event["tid"].clone(),
+                    start_ts: event["ts"].clone(),
+                    last_ts: event["ts"].clone(),
+                },
+            );
+        }
+    }
 
-    // Try to extract the commands from the command stream
+    for (_, run) in open_runs {
+        flush_sample_run(run, &mut result);
+    }
+
+    result
+}
+
+/// Runs a single source (command stream + profiling entries) through the full, non-streaming
+/// conversion pipeline and returns its events.
+fn process_source(source: &Source, args: &Args) -> Vec<Value> {
     let (mut agents, mut dma_rd_commands, mut dma_wr_commands, mut mce_commands, mut ple_commands) =
-        parse_command_stream(&args.command_stream);
+        parse_command_stream(&source.command_stream);
 
     let input_file = BufReader::new(
-        std::fs::File::open(args.profiling_entries).expect("Failed to open input JSON file"),
+        std::fs::File::open(&source.profiling_entries).expect("Failed to open input JSON file"),
     );
     let input_json: Value =
         serde_json::from_reader(input_file).expect("Failed to parse input JSON");
+    let decoder = decoder_for_schema_version(schema_version(&input_json));
 
-    let output_json = process_json(
-        input_json.as_array().expect("Invalid json"),
+    process_json(
+        schema_entries(&input_json),
+        decoder.as_ref(),
         &mut agents,
         &mut dma_rd_commands,
         &mut dma_wr_commands,
         &mut mce_commands,
         &mut ple_commands,
         args.add_timeline_bars,
-    );
-    let output_file = BufWriter::new(
+        args.clock_base.as_ref(),
+        args.legacy_format,
+    )
+}
+
+fn main() {
+    let args = Args::parse();
+
+    // Support a single source via the original --command-stream/--profiling-entries flags, for
+    // backwards compatibility, as well as one or more --source flags to merge multiple command
+    // streams / NPU cores / back-to-back captures into one interleaved trace.
+    let sources = if args.sources.is_empty() {
+        vec![Source {
+            command_stream: args.command_stream.clone(),
+            profiling_entries: args.profiling_entries.clone(),
+        }]
+    } else {
+        args.sources.clone()
+    };
+
+    let mut output_file = BufWriter::new(
         std::fs::File::create(&args.output).expect("Failed to create output JSON file"),
     );
-    serde_json::to_writer_pretty(output_file, &output_json).expect("Failed to save output JSON");
 
-    println!(
-        "Saved {} events to {}",
-        output_json.len(),
-        args.output.display()
+    assert!(
+        !(args.compact && args.format == Format::Perfetto),
+        "--compact does not support --format perfetto"
     );
+
+    // Stream straight to the output file, rather than buffering the whole trace in memory,
+    // whenever the rest of the flags allow it - not just when --streaming is explicitly passed.
+    // --format perfetto/html, --compact and --collapse-repeats all need the full event list
+    // up-front (to intern blobs, rebase/round timestamps, or look back at the previous sample),
+    // as does merging multiple --source inputs (to globally time-sort them), so those still go
+    // through the buffered path below.
+    let can_stream =
+        sources.len() == 1 && args.format == Format::Json && !args.compact && !args.collapse_repeats;
+    if args.streaming {
+        assert!(
+            can_stream,
+            "--streaming does not support --format perfetto or --format html, --compact, \
+             --collapse-repeats, or merging multiple --source inputs"
+        );
+    }
+
+    if can_stream {
+        let source = &sources[0];
+        let (mut agents, mut dma_rd_commands, mut dma_wr_commands, mut mce_commands, mut ple_commands) =
+            parse_command_stream(&source.command_stream);
+
+        let input_file = BufReader::new(
+            std::fs::File::open(&source.profiling_entries)
+                .expect("Failed to open input JSON file"),
+        );
+        let input_json: Value =
+            serde_json::from_reader(input_file).expect("Failed to parse input JSON");
+        let decoder = decoder_for_schema_version(schema_version(&input_json));
+
+        process_json_streaming(
+            schema_entries(&input_json),
+            decoder.as_ref(),
+            &mut agents,
+            &mut dma_rd_commands,
+            &mut dma_wr_commands,
+            &mut mce_commands,
+            &mut ple_commands,
+            &mut output_file,
+            args.clock_base.as_ref(),
+            args.legacy_format,
+        );
+
+        if args.legacy_format {
+            println!(
+                "Saved streamed events (not a closing ']' - this is expected) to {}",
+                args.output.display()
+            );
+        } else {
+            println!("Saved streamed events to {}", args.output.display());
+        }
+    } else {
+        let mut output_json = vec![];
+        for (idx, source) in sources.iter().enumerate() {
+            let mut events = process_source(source, &args);
+            if sources.len() > 1 {
+                apply_source_tag(&mut events, &format!("{idx:02}) "));
+            }
+            output_json.extend(events);
+        }
+
+        // Merge all sources into a single globally time-ordered event list. Metadata events
+        // (process/thread names) have no "ts" field; their relative order doesn't matter so they
+        // just sort to the front, which is harmless.
+        if sources.len() > 1 {
+            output_json.sort_by(|a, b| {
+                a["ts"]
+                    .as_f64()
+                    .unwrap_or(0.0)
+                    .total_cmp(&b["ts"].as_f64().unwrap_or(0.0))
+            });
+        }
+
+        if args.collapse_repeats {
+            output_json = collapse_repeated_samples(output_json);
+        }
+
+        if args.compact {
+            output_json = compact_events(output_json);
+        }
+
+        match args.format {
+            Format::Json => {
+                if args.legacy_format {
+                    serde_json::to_writer_pretty(output_file, &output_json)
+                        .expect("Failed to save output JSON");
+                } else {
+                    let wrapped_output =
+                        json!({ "traceEvents": output_json, "displayTimeUnit": "ns" });
+                    serde_json::to_writer_pretty(output_file, &wrapped_output)
+                        .expect("Failed to save output JSON");
+                }
+            }
+            Format::Perfetto => {
+                perfetto::write_perfetto_trace(&output_json, &mut output_file)
+                    .expect("Failed to save output Perfetto trace");
+            }
+            Format::Html => {
+                html::write_html_trace(&output_json, &mut output_file)
+                    .expect("Failed to save output HTML report");
+            }
+        }
+
+        println!(
+            "Saved {} events to {}",
+            output_json.len(),
+            args.output.display()
+        );
+    }
 }
@@ -0,0 +1,376 @@
+//
+// Copyright © 2023 Arm Limited. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A small, hand-rolled encoder for the Perfetto protobuf trace format (a stream of
+//! length-delimited `TracePacket` messages), used as a compact alternative to the Chrome JSON
+//! backend. Chrome JSON traces embed the full `text_representation` XML of every agent/command
+//! into `args`, so real captures balloon to hundreds of MB of mostly-duplicated text; this
+//! backend interns each distinct XML blob once and refers to it by id thereafter.
+//!
+//! We only implement the subset of the Perfetto schema needed here (track descriptors, track
+//! events and an interned string table), encoded directly with varints rather than pulling in a
+//! full generated-from-.proto client, in the same spirit as the hand-rolled XML reconstruction in
+//! `main.rs`.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v != 0 {
+            buf.push(byte | 0x80);
+        } else {
+            buf.push(byte);
+            break;
+        }
+    }
+}
+
+fn write_tag(buf: &mut Vec<u8>, field_number: u32, wire_type: u8) {
+    write_varint(buf, ((field_number as u64) << 3) | wire_type as u64);
+}
+
+fn write_varint_field(buf: &mut Vec<u8>, field_number: u32, v: u64) {
+    write_tag(buf, field_number, 0);
+    write_varint(buf, v);
+}
+
+fn write_bytes_field(buf: &mut Vec<u8>, field_number: u32, bytes: &[u8]) {
+    write_tag(buf, field_number, 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_string_field(buf: &mut Vec<u8>, field_number: u32, s: &str) {
+    write_bytes_field(buf, field_number, s.as_bytes());
+}
+
+fn write_message_field(buf: &mut Vec<u8>, field_number: u32, inner: &[u8]) {
+    write_bytes_field(buf, field_number, inner);
+}
+
+// perfetto.protos.TrackEvent.Type
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+// TracePacket field numbers we use.
+const FIELD_TIMESTAMP: u32 = 8;
+const FIELD_TRACK_EVENT: u32 = 11;
+const FIELD_TRACK_DESCRIPTOR: u32 = 60;
+const FIELD_INTERNED_DATA: u32 = 12;
+const FIELD_TRUSTED_PACKET_SEQUENCE_ID: u32 = 10;
+
+// TrackDescriptor field numbers.
+const FIELD_TD_UUID: u32 = 1;
+const FIELD_TD_NAME: u32 = 2;
+const FIELD_TD_PARENT_UUID: u32 = 5;
+const FIELD_TD_PROCESS: u32 = 4;
+const FIELD_TD_THREAD: u32 = 6;
+
+// ProcessDescriptor / ThreadDescriptor field numbers.
+const FIELD_PD_PID: u32 = 1;
+const FIELD_PD_NAME: u32 = 6;
+const FIELD_THD_PID: u32 = 1;
+const FIELD_THD_TID: u32 = 2;
+const FIELD_THD_NAME: u32 = 5;
+
+// TrackEvent field numbers.
+const FIELD_TE_CATEGORIES: u32 = 22;
+const FIELD_TE_NAME_IID: u32 = 10;
+const FIELD_TE_TYPE: u32 = 9;
+const FIELD_TE_TRACK_UUID: u32 = 11;
+const FIELD_TE_COUNTER_VALUE: u32 = 30;
+const FIELD_TE_DEBUG_ANNOTATIONS: u32 = 4;
+
+// DebugAnnotation field numbers.
+const FIELD_DA_NAME: u32 = 10;
+const FIELD_DA_STRING_VALUE: u32 = 6;
+const FIELD_DA_STRING_VALUE_IID: u32 = 19;
+
+// InternedData field numbers.
+const FIELD_ID_DEBUG_ANNOTATION_STRING_VALUES: u32 = 29;
+
+// A fixed sequence id identifying "this converter" as the single writer of the trace - Perfetto
+// uses this to scope incremental state (such as the interned string table) to a single producer.
+const SEQUENCE_ID: u64 = 1;
+
+fn track_descriptor_packet(uuid: u64, name: &str, parent_uuid: Option<u64>) -> Vec<u8> {
+    let mut descriptor = vec![];
+    write_varint_field(&mut descriptor, FIELD_TD_UUID, uuid);
+    write_string_field(&mut descriptor, FIELD_TD_NAME, name);
+    if let Some(parent_uuid) = parent_uuid {
+        write_varint_field(&mut descriptor, FIELD_TD_PARENT_UUID, parent_uuid);
+    }
+
+    let mut packet = vec![];
+    write_message_field(&mut packet, FIELD_TRACK_DESCRIPTOR, &descriptor);
+    packet
+}
+
+fn process_track_descriptor_packet(uuid: u64, pid: u64, name: &str) -> Vec<u8> {
+    let mut process = vec![];
+    write_varint_field(&mut process, FIELD_PD_PID, pid);
+    write_string_field(&mut process, FIELD_PD_NAME, name);
+
+    let mut descriptor = vec![];
+    write_varint_field(&mut descriptor, FIELD_TD_UUID, uuid);
+    write_message_field(&mut descriptor, FIELD_TD_PROCESS, &process);
+
+    let mut packet = vec![];
+    write_message_field(&mut packet, FIELD_TRACK_DESCRIPTOR, &descriptor);
+    packet
+}
+
+fn thread_track_descriptor_packet(uuid: u64, parent_uuid: u64, pid: u64, tid: u64, name: &str) -> Vec<u8> {
+    let mut thread = vec![];
+    write_varint_field(&mut thread, FIELD_THD_PID, pid);
+    write_varint_field(&mut thread, FIELD_THD_TID, tid);
+    write_string_field(&mut thread, FIELD_THD_NAME, name);
+
+    let mut descriptor = vec![];
+    write_varint_field(&mut descriptor, FIELD_TD_UUID, uuid);
+    write_varint_field(&mut descriptor, FIELD_TD_PARENT_UUID, parent_uuid);
+    write_message_field(&mut descriptor, FIELD_TD_THREAD, &thread);
+
+    let mut packet = vec![];
+    write_message_field(&mut packet, FIELD_TRACK_DESCRIPTOR, &descriptor);
+    packet
+}
+
+/// Interns a string (such as an `agent_xml`/`command_xml` blob) so each distinct value is written
+/// to the trace at most once, after which it is referred to by `iid`.
+struct StringInterner {
+    ids: HashMap<String, u64>,
+    next_iid: u64,
+}
+impl StringInterner {
+    fn new() -> Self {
+        StringInterner {
+            ids: HashMap::new(),
+            next_iid: 1,
+        }
+    }
+
+    /// Returns the iid for `s`, and if this is the first time we've seen it, also returns an
+    /// InternedData packet defining it.
+    fn intern(&mut self, s: &str) -> (u64, Option<Vec<u8>>) {
+        if let Some(iid) = self.ids.get(s) {
+            return (*iid, None);
+        }
+
+        let iid = self.next_iid;
+        self.next_iid += 1;
+        self.ids.insert(s.to_string(), iid);
+
+        let mut entry = vec![];
+        write_varint_field(&mut entry, 1, iid); // InternedString.iid
+        write_string_field(&mut entry, 2, s); // InternedString.str
+
+        let mut interned_data = vec![];
+        write_message_field(&mut interned_data, FIELD_ID_DEBUG_ANNOTATION_STRING_VALUES, &entry);
+
+        let mut packet = vec![];
+        write_varint_field(&mut packet, FIELD_TRUSTED_PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        write_message_field(&mut packet, FIELD_INTERNED_DATA, &interned_data);
+        (iid, Some(packet))
+    }
+}
+
+fn debug_annotation(name: &str, value: &Value, interner: &mut StringInterner, extra_packets: &mut Vec<Vec<u8>>) -> Vec<u8> {
+    let mut annotation = vec![];
+    write_string_field(&mut annotation, FIELD_DA_NAME, name);
+
+    match value {
+        // Large, highly-repeated blobs (the agent/command XML) are interned; everything else is
+        // just written inline as a string, which is good enough for display purposes.
+        Value::String(s) if name == "agent_xml" || name == "command_xml" => {
+            let (iid, new_entry) = interner.intern(s);
+            if let Some(packet) = new_entry {
+                extra_packets.push(packet);
+            }
+            write_varint_field(&mut annotation, FIELD_DA_STRING_VALUE_IID, iid);
+        }
+        Value::String(s) => write_string_field(&mut annotation, FIELD_DA_STRING_VALUE, s),
+        other => write_string_field(&mut annotation, FIELD_DA_STRING_VALUE, &other.to_string()),
+    }
+
+    annotation
+}
+
+fn track_event_packet(
+    ts: u64,
+    track_uuid: u64,
+    event_type: u64,
+    name: Option<&str>,
+    category: &str,
+    args: Option<&serde_json::Map<String, Value>>,
+    counter_value: Option<i64>,
+    interner: &mut StringInterner,
+) -> Vec<u8> {
+    let mut extra_packets = vec![];
+
+    let mut track_event = vec![];
+    write_varint_field(&mut track_event, FIELD_TE_TYPE, event_type);
+    write_varint_field(&mut track_event, FIELD_TE_TRACK_UUID, track_uuid);
+    if !category.is_empty() {
+        write_string_field(&mut track_event, FIELD_TE_CATEGORIES, category);
+    }
+    if let Some(name) = name {
+        let (iid, new_entry) = interner.intern(name);
+        if let Some(packet) = new_entry {
+            extra_packets.push(packet);
+        }
+        write_varint_field(&mut track_event, FIELD_TE_NAME_IID, iid);
+    }
+    if let Some(counter_value) = counter_value {
+        write_tag(&mut track_event, FIELD_TE_COUNTER_VALUE, 0);
+        write_varint(&mut track_event, counter_value as u64);
+    }
+    if let Some(args) = args {
+        for (key, value) in args {
+            // "entry" duplicates the whole raw input entry and isn't useful once interned/typed
+            // fields have been extracted from it, so we skip it to avoid doubling trace size.
+            if key == "entry" {
+                continue;
+            }
+            let annotation = debug_annotation(key, value, interner, &mut extra_packets);
+            write_message_field(&mut track_event, FIELD_TE_DEBUG_ANNOTATIONS, &annotation);
+        }
+    }
+
+    let mut packet = vec![];
+    write_varint_field(&mut packet, FIELD_TIMESTAMP, ts);
+    write_varint_field(&mut packet, FIELD_TRUSTED_PACKET_SEQUENCE_ID, SEQUENCE_ID);
+    write_message_field(&mut packet, FIELD_TRACK_EVENT, &track_event);
+
+    let mut result = vec![];
+    for extra in extra_packets {
+        result.extend(length_delimited(&extra));
+    }
+    result.extend(length_delimited(&packet));
+    result
+}
+
+/// perfetto.protos.Trace is just `repeated TracePacket packet = 1;` - each packet is written as
+/// its own length-delimited field 1.
+fn length_delimited(packet: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    write_bytes_field(&mut buf, 1, packet);
+    buf
+}
+
+/// Converts the Chrome-style trace events already produced by `process_json` (which already
+/// classified each entry via `process_timeline_event_start_or_instant`/`process_counter_entry`)
+/// into a Perfetto protobuf trace, and writes it to `writer`.
+pub fn write_perfetto_trace(events: &[Value], writer: &mut impl Write) -> io::Result<()> {
+    let mut interner = StringInterner::new();
+    let mut process_tracks_written = std::collections::HashSet::new();
+    let mut thread_tracks_written = std::collections::HashSet::new();
+    let mut counter_tracks_written = std::collections::HashSet::new();
+
+    for event in events {
+        let ph = event["ph"].as_str().unwrap_or("");
+
+        if ph == "M" {
+            // Metadata events assign display names; turn them into track descriptors.
+            let name = event["name"].as_str().unwrap_or("");
+            let args_name = event["args"]["name"].as_str().unwrap_or("").to_string();
+            if name == "process_name" {
+                let pid = event["pid"].as_u64().unwrap();
+                if process_tracks_written.insert(pid) {
+                    writer.write_all(&length_delimited(&process_track_descriptor_packet(
+                        pid, pid, &args_name,
+                    )))?;
+                }
+            } else if name == "thread_name" {
+                let pid = event["pid"].as_u64().unwrap();
+                let tid = event["tid"].as_u64().unwrap();
+                let track_uuid = thread_track_uuid(pid, tid);
+                if thread_tracks_written.insert((pid, tid)) {
+                    writer.write_all(&length_delimited(&thread_track_descriptor_packet(
+                        track_uuid, pid, pid, tid, &args_name,
+                    )))?;
+                }
+            }
+            continue;
+        }
+
+        let pid = event["pid"].as_u64().unwrap_or(0);
+        let tid = event["tid"].as_u64().unwrap_or(0);
+        // "ts" is a microsecond Chrome timestamp, which may be fractional (see `chrome_ts` in
+        // main.rs); Perfetto's TrackEvent timestamps are integer nanoseconds, so round-trip via
+        // that same unit.
+        let ts = (event["ts"].as_f64().unwrap_or(0.0) * 1000.0).round() as u64;
+        let name = event["name"].as_str();
+        let category = event["cname"].as_str().unwrap_or("");
+        let args = event["args"].as_object();
+
+        match ph {
+            "B" | "E" | "I" => {
+                let track_uuid = thread_track_uuid(pid, tid);
+                let event_type = match ph {
+                    "B" => TYPE_SLICE_BEGIN,
+                    "E" => TYPE_SLICE_END,
+                    _ => TYPE_INSTANT,
+                };
+                writer.write_all(&track_event_packet(
+                    ts,
+                    track_uuid,
+                    event_type,
+                    name,
+                    category,
+                    args,
+                    None,
+                    &mut interner,
+                ))?;
+            }
+            "C" => {
+                let track_uuid = counter_track_uuid(pid, name.unwrap_or(""));
+                if counter_tracks_written.insert(track_uuid) {
+                    writer.write_all(&length_delimited(&track_descriptor_packet(
+                        track_uuid,
+                        name.unwrap_or(""),
+                        None,
+                    )))?;
+                }
+                // Look up the value by its known key (the counter's own name, per
+                // `process_counter_entry` in main.rs) rather than assuming it's the only key in
+                // "args" - main.rs also inserts a "wall_clock" key when --clock-base is given.
+                let counter_value = args
+                    .and_then(|a| a.get(name.unwrap_or("")))
+                    .and_then(Value::as_i64)
+                    .unwrap_or(0);
+                writer.write_all(&track_event_packet(
+                    ts,
+                    track_uuid,
+                    TYPE_COUNTER,
+                    None,
+                    category,
+                    None,
+                    Some(counter_value),
+                    &mut interner,
+                ))?;
+            }
+            // Flow events ("s"/"f") aren't part of the base TrackEvent schema used here; skipping
+            // them keeps this backend to the subset of Perfetto's format we've implemented.
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn thread_track_uuid(pid: u64, tid: u64) -> u64 {
+    crate::hash_string(&format!("perfetto-thread-track:{pid}:{tid}"))
+}
+
+fn counter_track_uuid(pid: u64, name: &str) -> u64 {
+    crate::hash_string(&format!("perfetto-counter-track:{pid}:{name}"))
+}